@@ -14,7 +14,9 @@ use fxhash::FxHashSet;
 use regex::bytes::Regex;
 use itertools::Itertools;
 use std::collections::VecDeque;
-use std::io::{BufRead, StdinLock, Write};
+use std::io::{BufRead, BufWriter, StdinLock, Write};
+use std::sync::mpsc::sync_channel;
+use std::thread;
 use failure::Error;
 
 #[derive(Debug, Fail)]
@@ -23,24 +25,47 @@ enum UqError {
     InvalidRegex {
         regex: String,
     },
+    #[fail(display = "cache capacity exceeded: {} bytes would be in use, limit is {}", bytes_used, limit)]
+    CapacityExceeded {
+        bytes_used: usize,
+        limit: usize,
+    },
+    #[fail(display = "cache capacity exceeded: {} entries stored, limit is {}", entries, limit)]
+    EntryCapacityExceeded {
+        entries: usize,
+        limit: usize,
+    },
+    // `bytes` is the estimated size of the entry being stored, not the size
+    // of the allocation that failed.
+    #[fail(display = "failed to allocate memory while storing an entry of about {} bytes", bytes)]
+    AllocationFailed {
+        bytes: usize,
+    },
 }
 
+// Rough per-entry bookkeeping cost (the `Vec<u8>` header, which is what each
+// hash-table slot holds) added on top of each line's own length when
+// budgeting by memory.
+const ENTRY_OVERHEAD: usize = std::mem::size_of::<Vec<u8>>();
+
 struct StdinReader<'a> {
     buffer: Vec<u8>,
     input: StdinLock<'a>,
+    delimiter: u8,
 }
 
 impl<'a> StdinReader<'a> {
-    fn new(input: StdinLock<'a>) -> Self {
+    fn new(input: StdinLock<'a>, delimiter: u8) -> Self {
         Self {
             buffer: Vec::new(),
             input: input,
+            delimiter,
         }
     }
 
     fn next_line(&mut self) -> Option<&Vec<u8>> {
         self.buffer.clear();
-        match self.input.read_until(b'\n', &mut self.buffer) {
+        match self.input.read_until(self.delimiter, &mut self.buffer) {
             Ok(0) => None,
             Ok(_) => Some(&self.buffer),
             Err(e) => panic!("Failed reading line: {}", e),
@@ -49,12 +74,14 @@ impl<'a> StdinReader<'a> {
 }
 
 trait UniqueSet<T> {
-    fn insert(&mut self, value: T) -> bool;
+    fn insert(&mut self, value: T) -> Result<bool, UqError>;
 }
 
 impl UniqueSet<Vec<u8>> for FxHashSet<Vec<u8>> {
-    fn insert(&mut self, value: Vec<u8>) -> bool {
-        self.insert(value)
+    fn insert(&mut self, value: Vec<u8>) -> Result<bool, UqError> {
+        self.try_reserve(1)
+            .map_err(|_| UqError::AllocationFailed { bytes: value.len() + ENTRY_OVERHEAD })?;
+        Ok(FxHashSet::insert(self, value))
     }
 }
 
@@ -74,15 +101,64 @@ impl UniqueWithCap {
 }
 
 impl UniqueSet<Vec<u8>> for UniqueWithCap {
-    fn insert(&mut self, value: Vec<u8>) -> bool {
+    fn insert(&mut self, value: Vec<u8>) -> Result<bool, UqError> {
+        let bytes = value.len() + ENTRY_OVERHEAD;
+        self.lines
+            .try_reserve(1)
+            .map_err(|_| UqError::AllocationFailed { bytes })?;
         if self.lines.insert(value) {
             if self.lines.len() > self.cap {
-                panic!("Cache capacity exceeded!");
+                return Err(UqError::EntryCapacityExceeded {
+                    entries: self.lines.len(),
+                    limit: self.cap,
+                });
             }
-            true
+            Ok(true)
         } else {
-            false
+            Ok(false)
+        }
+    }
+}
+
+/// Like [`UniqueWithCap`], but the cap is expressed in bytes of stored data
+/// rather than a number of entries, which tracks real memory pressure more
+/// closely when line lengths vary.
+struct UniqueWithMemoryCap {
+    lines: FxHashSet<Vec<u8>>,
+    bytes_used: usize,
+    limit: usize,
+}
+
+impl UniqueWithMemoryCap {
+    fn new(limit: usize) -> Self {
+        UniqueWithMemoryCap {
+            lines: FxHashSet::default(),
+            bytes_used: 0,
+            limit,
+        }
+    }
+}
+
+impl UniqueSet<Vec<u8>> for UniqueWithMemoryCap {
+    fn insert(&mut self, value: Vec<u8>) -> Result<bool, UqError> {
+        if self.lines.contains(&value) {
+            return Ok(false);
+        }
+
+        let bytes = value.len() + ENTRY_OVERHEAD;
+        if self.bytes_used + bytes > self.limit {
+            return Err(UqError::CapacityExceeded {
+                bytes_used: self.bytes_used + bytes,
+                limit: self.limit,
+            });
         }
+
+        self.lines
+            .try_reserve(1)
+            .map_err(|_| UqError::AllocationFailed { bytes })?;
+        self.lines.insert(value);
+        self.bytes_used += bytes;
+        Ok(true)
     }
 }
 
@@ -104,21 +180,80 @@ impl UniqueWithOverride {
 
 
 impl UniqueSet<Vec<u8>> for UniqueWithOverride {
-    fn insert(&mut self, value: Vec<u8>) -> bool {
+    fn insert(&mut self, value: Vec<u8>) -> Result<bool, UqError> {
+        let bytes = value.len() + ENTRY_OVERHEAD;
+        self.set
+            .try_reserve(1)
+            .map_err(|_| UqError::AllocationFailed { bytes })?;
+        self.queue
+            .try_reserve(1)
+            .map_err(|_| UqError::AllocationFailed { bytes })?;
         if self.set.insert(value.clone()) {
             if self.set.len() > self.cap {
                 self.set.remove(&self.queue.pop_front().unwrap());
             }
 
             self.queue.push_back(value);
-            true
+            Ok(true)
         } else {
-            false
+            Ok(false)
         }
     }
 }
 
 
+/// A bit salted so the second base hash is independent of the first, used to
+/// seed double hashing.
+const BLOOM_SALT: &[u8] = b"uq-bloom";
+
+/// Approximate, bounded-memory backend that stores membership in a Bloom
+/// filter rather than keeping every record.
+///
+/// Each record is probed at `k` positions derived by double hashing from two
+/// base hashes (`fxhash` of the record, and `fxhash` of the record with
+/// [`BLOOM_SALT`] prepended): `h_i = (h1 + i * h2) mod m`. A record is novel
+/// iff at least one of its `k` bits was still unset. Memory is fixed at `m`
+/// bits regardless of stream size, at the cost of a false-duplicate rate of
+/// roughly `(1 - e^{-kn/m})^k` for `n` distinct records — i.e. a small
+/// fraction of genuinely-unique records are silently dropped.
+struct BloomFilter {
+    bits: Vec<u64>,
+    m: usize,
+    k: usize,
+}
+
+impl BloomFilter {
+    fn new(m: usize, k: usize) -> Self {
+        let m = m.max(1);
+        BloomFilter {
+            bits: vec![0u64; (m + 63) / 64],
+            m,
+            k,
+        }
+    }
+}
+
+impl UniqueSet<Vec<u8>> for BloomFilter {
+    fn insert(&mut self, value: Vec<u8>) -> Result<bool, UqError> {
+        let h1 = fxhash::hash(&value);
+        let mut salted = Vec::with_capacity(BLOOM_SALT.len() + value.len());
+        salted.extend_from_slice(BLOOM_SALT);
+        salted.extend_from_slice(&value);
+        let h2 = fxhash::hash(&salted);
+
+        let mut novel = false;
+        for i in 0..self.k {
+            let probe = h1.wrapping_add(i.wrapping_mul(h2)) % self.m;
+            let (word, mask) = (probe / 64, 1u64 << (probe % 64));
+            if self.bits[word] & mask == 0 {
+                novel = true;
+                self.bits[word] |= mask;
+            }
+        }
+        Ok(novel)
+    }
+}
+
 struct IncludeFilter {
     re: Regex,
 }
@@ -189,6 +324,172 @@ impl LineFilter for ExcludeFilter {
 }
 
 
+fn build_set(
+    capacity: Option<usize>,
+    override_: bool,
+    max_memory: Option<usize>,
+    approx: Option<(usize, usize)>,
+) -> Box<UniqueSet<Vec<u8>>> {
+    match approx {
+        Some((m, k)) => Box::new(BloomFilter::new(m, k)),
+        None => match (max_memory, capacity, override_) {
+            (Some(limit), _, _) => Box::new(UniqueWithMemoryCap::new(limit)),
+            (None, Some(capacity), true) => Box::new(UniqueWithOverride::new(capacity)),
+            (None, Some(capacity), false) => Box::new(UniqueWithCap::new(capacity)),
+            _ => Box::new(FxHashSet::default()),
+        },
+    }
+}
+
+/// Deduplicate the stream across `jobs` shards.
+///
+/// A line's shard is chosen by `fxhash(key) % jobs`, so a given key always
+/// lands in the same shard and no cross-shard locking is needed. The reader
+/// (this thread) tags each post-filter record with a sequence number and
+/// dispatches it to its shard; each worker tests/inserts in its own backing
+/// set and forwards the record to the writer, which restores the original
+/// input order with a small reorder buffer. The `--capacity`/`--override`
+/// limits apply per shard, so they act as an approximate global cap.
+fn run_parallel(
+    jobs: usize,
+    mut reader: StdinReader,
+    filter: Option<Box<LineFilter>>,
+    capacity: Option<usize>,
+    override_: bool,
+    max_memory: Option<usize>,
+    approx: Option<(usize, usize)>,
+) -> Result<(), UqError> {
+    const CHANNEL_BOUND: usize = 1024;
+
+    let (writer_tx, writer_rx) = sync_channel::<(usize, Option<Vec<u8>>)>(CHANNEL_BOUND);
+
+    let mut worker_txs = Vec::with_capacity(jobs);
+    let mut workers = Vec::with_capacity(jobs);
+    for _ in 0..jobs {
+        let (tx, rx) = sync_channel::<(usize, Vec<u8>, Vec<u8>)>(CHANNEL_BOUND);
+        worker_txs.push(tx);
+        let writer_tx = writer_tx.clone();
+        let handle = thread::spawn(move || -> Result<(), UqError> {
+            let mut set = build_set(capacity, override_, max_memory, approx);
+            for (seq, key, line) in rx {
+                let novel = set.insert(key)?;
+                // A send error means the writer has gone away (an error was
+                // reported elsewhere), so there is nothing left to do.
+                if writer_tx
+                    .send((seq, if novel { Some(line) } else { None }))
+                    .is_err()
+                {
+                    break;
+                }
+            }
+            Ok(())
+        });
+        workers.push(handle);
+    }
+    // Only the workers' clones should keep the writer channel alive.
+    drop(writer_tx);
+
+    let writer = thread::spawn(move || {
+        use std::collections::HashMap;
+        let stdout = std::io::stdout();
+        let mut out = BufWriter::new(stdout.lock());
+        let mut pending: HashMap<usize, Option<Vec<u8>>> = HashMap::new();
+        let mut next = 0usize;
+        for (seq, line) in writer_rx {
+            pending.insert(seq, line);
+            while let Some(slot) = pending.remove(&next) {
+                if let Some(line) = slot {
+                    out.write_all(&line).expect("Failed writing line");
+                }
+                next += 1;
+            }
+        }
+        out.flush().expect("Failed flushing output");
+    });
+
+    let mut seq = 0usize;
+    while let Some(line) = reader.next_line() {
+        let key = match &filter {
+            Some(filter) => match filter.apply(line) {
+                Some(key) => key,
+                None => continue,
+            },
+            None => line.clone(),
+        };
+        let shard = fxhash::hash(&key) % jobs;
+        if worker_txs[shard].send((seq, key, line.clone())).is_err() {
+            // A worker died; its error is surfaced when we join below.
+            break;
+        }
+        seq += 1;
+    }
+
+    drop(worker_txs);
+
+    for handle in workers {
+        handle.join().expect("worker thread panicked")?;
+    }
+    writer.join().expect("writer thread panicked");
+
+    Ok(())
+}
+
+/// Collapse runs of adjacent records sharing the same post-filter key, in the
+/// manner of the classic `uniq` tool on sorted input. Unlike the set-based
+/// backends this keeps only the current run, so it runs in constant memory.
+///
+/// `count` prefixes each emitted record with its run length; `only_dup` and
+/// `only_uniq` restrict output to records that did or did not repeat. The key
+/// drives comparison and counting, but the original input line is emitted.
+fn run_adjacent<W: Write>(
+    mut reader: StdinReader,
+    filter: Option<Box<LineFilter>>,
+    mut output: W,
+    count: bool,
+    only_dup: bool,
+    only_uniq: bool,
+) -> Result<(), UqError> {
+    let mut emit = |line: &[u8], run: usize| {
+        if (only_dup && run < 2) || (only_uniq && run > 1) {
+            return;
+        }
+        if count {
+            output
+                .write_all(format!("{:>7} ", run).as_bytes())
+                .expect("Failed writing line");
+        }
+        output.write_all(line).expect("Failed writing line");
+    };
+
+    let mut current: Option<(Vec<u8>, Vec<u8>, usize)> = None;
+    while let Some(line) = reader.next_line() {
+        let key = match &filter {
+            Some(filter) => match filter.apply(line) {
+                Some(key) => key,
+                None => continue,
+            },
+            None => line.clone(),
+        };
+
+        match current {
+            Some((ref ckey, _, ref mut run)) if *ckey == key => *run += 1,
+            _ => {
+                if let Some((_, line, run)) = current.take() {
+                    emit(&line, run);
+                }
+                current = Some((key, line.clone(), 1));
+            }
+        }
+    }
+    if let Some((_, line, run)) = current.take() {
+        emit(&line, run);
+    }
+
+    output.flush().expect("Failed flushing output");
+
+    Ok(())
+}
+
 fn main() -> Result<(), UqError> {
     let matches = App::new("uq (lostutils)")
         .arg(
@@ -206,6 +507,90 @@ fn main() -> Result<(), UqError> {
                 .value_name("override")
                 .takes_value(false),
         )
+        .arg(
+            Arg::with_name("max-memory")
+                .long("max-memory")
+                .help("Budget the unique set by bytes stored rather than entry count.\nuq exits non-zero when the budget is exceeded.")
+                .value_name("bytes")
+                .conflicts_with("capacity")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("jobs")
+                .long("jobs")
+                .short("j")
+                .help("Deduplicate using N sharded worker threads.\nLimits apply per shard, so they act as an approximate global cap.")
+                .value_name("N")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("zero-terminated")
+                .long("zero-terminated")
+                .short("z")
+                .help("Input and output records are terminated by a NUL byte rather than a newline.")
+                .conflicts_with("delimiter")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("delimiter")
+                .long("delimiter")
+                .help("Use the first byte of the given value to separate records, in and out.")
+                .value_name("byte")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("adjacent")
+                .long("adjacent")
+                .help("Only suppress records identical to the immediately preceding one\n(like classic uniq on sorted input); runs in constant memory.")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("count")
+                .long("count")
+                .short("c")
+                .help("Prefix each emitted record with the number of consecutive occurrences.")
+                .requires("adjacent")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("duplicates")
+                .long("duplicates")
+                .short("d")
+                .help("Only emit records that repeated.")
+                .requires("adjacent")
+                .conflicts_with("unique")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("unique")
+                .long("unique")
+                .short("u")
+                .help("Only emit records that never repeated.")
+                .requires("adjacent")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("approx")
+                .long("approx")
+                .help("Approximate dedup using a Bloom filter: O(1) memory, at the cost of\nsilently dropping a small fraction of unique records (false duplicates).")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::with_name("bits")
+                .long("bits")
+                .help("Size of the Bloom filter bit array, m. Larger m lowers the false-duplicate rate.")
+                .value_name("m")
+                .requires("approx")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("hashes")
+                .long("hashes")
+                .help("Number of probe bits per record, k.")
+                .value_name("k")
+                .requires("approx")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("include")
                 .long("include")
@@ -231,17 +616,53 @@ fn main() -> Result<(), UqError> {
         None => None,
     };
 
-    let mut unique_filter: Box<UniqueSet<Vec<u8>>> = match (capacity, matches.is_present("override")) {
-        (Some(capacity), true) => Box::new(UniqueWithOverride::new(capacity)),
-        (Some(capacity), false) => Box::new(UniqueWithCap::new(capacity)),
-        _ => Box::new(FxHashSet::default()),
+    let max_memory = match matches.value_of("max-memory") {
+        Some(n) => match n.parse::<usize>() {
+            Ok(n) => Some(n),
+            Err(_) => None,
+        },
+        None => None,
+    };
+
+    let override_ = matches.is_present("override");
+
+    // Default to a ~1 MiB (8 Mbit) filter with 3 probes when sizes are omitted.
+    let approx = if matches.is_present("approx") {
+        let m = matches
+            .value_of("bits")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(1 << 23);
+        // k must be at least 1 probe: with k == 0 the filter never sets a bit
+        // and treats every record as a duplicate, silently eating the stream.
+        let k = matches
+            .value_of("hashes")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(3)
+            .max(1);
+        Some((m, k))
+    } else {
+        None
     };
 
+    let jobs = match matches.value_of("jobs") {
+        Some(n) => n.parse::<usize>().ok().filter(|&n| n > 0),
+        None => None,
+    };
+
+    let delimiter: u8 = if matches.is_present("zero-terminated") {
+        b'\0'
+    } else {
+        match matches.value_of("delimiter") {
+            Some(d) => d.as_bytes().first().copied().unwrap_or(b'\n'),
+            None => b'\n',
+        }
+    };
 
     let (_in, _out) = (std::io::stdin(), std::io::stdout());
-    let (input, mut output) = (_in.lock(), _out.lock());
+    let (input, _out_lock) = (_in.lock(), _out.lock());
+    let mut output = BufWriter::new(_out_lock);
 
-    let mut stdin_reader = StdinReader::new(input);
+    let mut stdin_reader = StdinReader::new(input, delimiter);
 
     let filter: Option<Box<LineFilter>> = match (matches.value_of("include"),
                                                  matches.value_of("exclude")) {
@@ -250,16 +671,37 @@ fn main() -> Result<(), UqError> {
         _ => None,
     };
 
+    if matches.is_present("adjacent") {
+        return run_adjacent(
+            stdin_reader,
+            filter,
+            output,
+            matches.is_present("count"),
+            matches.is_present("duplicates"),
+            matches.is_present("unique"),
+        );
+    }
+
+    if let Some(jobs) = jobs {
+        if jobs > 1 {
+            // Release our StdoutLock before the writer thread re-locks stdout;
+            // the lock is not re-entrant across threads and would deadlock.
+            drop(output);
+            return run_parallel(jobs, stdin_reader, filter, capacity, override_, max_memory, approx);
+        }
+    }
+
+    let mut unique_filter: Box<UniqueSet<Vec<u8>>> = build_set(capacity, override_, max_memory, approx);
 
     while let Some(line) = stdin_reader.next_line() {
         let is_unique = match &filter {
             Some(filter) =>
                 match filter.apply(line) {
-                    Some(line) => unique_filter.insert(line.clone()),
+                    Some(line) => unique_filter.insert(line.clone())?,
                     None => false,
                 }
             None =>
-                unique_filter.insert(line.clone()),
+                unique_filter.insert(line.clone())?,
         };
 
 
@@ -268,5 +710,7 @@ fn main() -> Result<(), UqError> {
         }
     }
 
+    output.flush().expect("Failed flushing output");
+
     Ok(())
 }